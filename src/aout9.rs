@@ -1,7 +1,5 @@
 #![allow(non_upper_case_globals, non_camel_case_types, dead_code)]
 
-use std::mem::size_of;
-use std::slice::from_raw_parts;
 use std::io::{Write, Result};
 
 use byteorder::{BigEndian, WriteBytesExt};
@@ -56,22 +54,6 @@ pub struct Header {
 	pcsz:	u32,		/* size of pc/line number table */
 }
 
-impl Header {
-	fn to_be(&self) -> [u32; 8] {
-		use std::intrinsics::bswap32;
-		unsafe {[
-			bswap32(self.magic),
-			bswap32(self.text),
-			bswap32(self.data),
-			bswap32(self.bss),
-			bswap32(self.syms),
-			bswap32(self.entry),
-			bswap32(self.spsz),
-			bswap32(self.pcsz),
-		]}
-	}
-}
-
 /// Header, text, data, symbols, PC/SP, PC/SZ.
 ///
 /// The symbol, PC/SP, and PC/SZ tables are not supported. That is, this is
@@ -86,30 +68,33 @@ pub struct AOut9 {
 
 impl AOut9 {
 	pub fn write_to<T: Write>(&self, mut sink: T) -> Result<()> {
-		let header = Header {
-			magic: self.magic as u32,
-			text: self.text.len() as u32,
-			data: self.data.len() as u32,
-			bss: self.bss as u32,
-			syms: 0,
-			entry: self.entry as u32 + 0x1000,
-			spsz: 0,
-			pcsz: 0,
-		};
+		let magic = self.magic as u32;
 
-		let h = unsafe {from_raw_parts(
-			&header as *const Header as *const u32,
-			size_of::<Header>() / size_of::<u32>()
-		)};
+		// The eight big-endian words that open every Plan 9 a.out.
+		let header: [u32; 8] = [
+			magic,
+			self.text.len() as u32,
+			self.data.len() as u32,
+			self.bss as u32,
+			0,				/* syms */
+			self.entry as u32,
+			0,				/* spsz */
+			0,				/* pcsz */
+		];
 
-		for dword in h {
-			sink.write_u32::<BigEndian>(*dword).unwrap();
+		for dword in header.iter() {
+			try!(sink.write_u32::<BigEndian>(*dword));
 		}
 
-		let result =      sink.write_all(&self.text[..])
-			.and_then(|_| sink.write_all(&self.data[..]));
+		// 64-bit targets set the Hdr bit, signalling an expanded header whose
+		// full entry point follows the eight words as a big-endian quadword.
+		if magic & Hdr != 0 {
+			try!(sink.write_u64::<BigEndian>(self.entry));
+		}
 
-		result
+		try!(sink.write_all(&self.text[..]));
+		try!(sink.write_all(&self.data[..]));
+		Ok(())
 	}
 }
 
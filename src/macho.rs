@@ -3,10 +3,110 @@
 
 #![allow(non_upper_case_globals, non_camel_case_types, dead_code)]
 
+use std::fmt;
+use std::io::{self, Write};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use Error;
+use Error::*;
+
 pub type VmProt = u32; // c_int
 // pub type U8N16 = ((u8,u8,u8,u8, u8,u8,u8,u8), (u8,u8,u8,u8, u8,u8,u8,u8,));
 pub type U8N16 = [u8; 16];
 
+/// The byte order of a Mach-O file, derived from its magic. Big-endian files
+/// carry the swapped `0xcefaedfe`/`0xcffaedfe` magics.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Endian {
+	Little,
+	Big,
+}
+
+/// Determine a Mach-O's byte order from its leading magic without assuming the
+/// host's endianness.
+pub fn endian_of(buf: &[u8]) -> Result<Endian, Error> {
+	if buf.len() < 4 {return Err(TooShort)};
+	let le = (buf[0] as u32) | (buf[1] as u32) << 8
+		| (buf[2] as u32) << 16 | (buf[3] as u32) << 24;
+	match le {
+		0xfeedface | 0xfeedfacf => Ok(Endian::Little),
+		0xcefaedfe | 0xcffaedfe => Ok(Endian::Big),
+		m => Err(UnknownMagic(m)),
+	}
+}
+
+/// A forward byte cursor that length-checks every read and honors the file's
+/// endianness, so malformed input yields `Error::TooShort` instead of UB.
+pub struct Reader<'a> {
+	buf: &'a [u8],
+	pos: usize,
+	endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+	pub fn new(buf: &'a [u8], endian: Endian) -> Reader<'a> {
+		Reader {buf: buf, pos: 0, endian: endian}
+	}
+
+	fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+		if self.buf.len() < self.pos + n {return Err(TooShort)};
+		let s = &self.buf[self.pos..self.pos + n];
+		self.pos += n;
+		Ok(s)
+	}
+
+	pub fn u8(&mut self) -> Result<u8, Error> {
+		Ok(try!(self.take(1))[0])
+	}
+
+	pub fn u16(&mut self) -> Result<u16, Error> {
+		let b = try!(self.take(2));
+		Ok(match self.endian {
+			Endian::Little => (b[0] as u16) | (b[1] as u16) << 8,
+			Endian::Big    => (b[1] as u16) | (b[0] as u16) << 8,
+		})
+	}
+
+	pub fn u32(&mut self) -> Result<u32, Error> {
+		let b = try!(self.take(4));
+		Ok(match self.endian {
+			Endian::Little => (b[0] as u32) | (b[1] as u32) << 8
+				| (b[2] as u32) << 16 | (b[3] as u32) << 24,
+			Endian::Big    => (b[3] as u32) | (b[2] as u32) << 8
+				| (b[1] as u32) << 16 | (b[0] as u32) << 24,
+		})
+	}
+
+	pub fn u64(&mut self) -> Result<u64, Error> {
+		let (lo, hi) = match self.endian {
+			Endian::Little => {
+				let lo = try!(self.u32()); (lo, try!(self.u32()))
+			},
+			Endian::Big => {
+				let hi = try!(self.u32()); (try!(self.u32()), hi)
+			},
+		};
+		Ok((lo as u64) | (hi as u64) << 32)
+	}
+
+	/// Read a fixed 16-byte field (segment/section names, UUIDs), unswapped.
+	pub fn bytes16(&mut self) -> Result<U8N16, Error> {
+		let b = try!(self.take(16));
+		let mut out = [0u8; 16];
+		for i in range(0, 16) {out[i] = b[i]};
+		Ok(out)
+	}
+}
+
+/// A header or load-command struct that can be parsed from a byte slice in a
+/// bounds-checked, endian-aware way, replacing the old `reinterpret_copy`.
+pub trait FromBytes: Sized {
+	/// On-disk size in bytes.
+	const SIZE: usize;
+	fn read_from(buf: &[u8], endian: Endian) -> Result<Self, Error>;
+}
+
 /// Indicates a 32 or 64-bit Mach-O file.
 #[derive(PartialEq, Eq)]
 #[repr(packed)]
@@ -21,6 +121,39 @@ impl Magic {
 }
 
 
+/// Magic of a fat (universal) archive, stored big-endian on disk.
+pub const FAT_MAGIC: u32	= 0xcafebabe;
+/// Magic of a fat archive whose member offsets/sizes are 64-bit.
+pub const FAT_MAGIC_64: u32	= 0xcafebabf;
+
+/// The header opening a fat archive. All fields are big-endian on disk.
+#[repr(packed)]
+pub struct FatHeader {
+	pub magic: u32,
+	pub nfat_arch: u32,
+}
+
+/// One member of a fat archive, locating a single-architecture Mach-O.
+#[repr(packed)]
+pub struct FatArch {
+	pub cputype: u32,
+	pub cpusubtype: u32,
+	pub offset: u32,
+	pub size: u32,
+	pub align: u32,
+}
+
+/// The 64-bit `FatArch`, widening `offset`/`size` to address large members.
+#[repr(packed)]
+pub struct FatArch64 {
+	pub cputype: u32,
+	pub cpusubtype: u32,
+	pub offset: u64,
+	pub size: u64,
+	pub align: u32,
+	pub reserved: u32,
+}
+
 const ArchAbi64: u32	= 0x01_000000;
 /// Indicates the architecture you intend to use the file on.
 #[repr(u32)]
@@ -63,28 +196,28 @@ pub enum Flags {
 #[repr(packed)]
 pub struct Header {
 	pub magic: Magic,
-	pub cputype: CpuType, // c_int
-	pub cpusubtype: CpuSubtype, // c_int
-	pub filetype: Filetype,
+	pub cputype: u32, // CpuType, c_int
+	pub cpusubtype: u32, // CpuSubtype, c_int
+	pub filetype: u32, // Filetype
 	pub ncmds: u32,
 	pub sizeofcmds: u32,
-	pub flags: Flags,
+	pub flags: u32, // Flags
 	// pub reserved: (),
 }
 
 #[repr(packed)]
 pub struct Header64 {
 	pub magic: Magic,
-	pub cputype: CpuType, // c_int
-	pub cpusubtype: CpuSubtype, // c_int
-	pub filetype: Filetype,
+	pub cputype: u32, // CpuType, c_int
+	pub cpusubtype: u32, // CpuSubtype, c_int
+	pub filetype: u32, // Filetype
 	pub ncmds: u32,
 	pub sizeofcmds: u32,
-	pub flags: Flags,
+	pub flags: u32, // Flags
 	pub reserved: u32,
 }
 
-#[derive(PartialEq)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(packed)]
 pub struct LoadCommandType(pub u32);
 pub const Segment32:	LoadCommandType = LoadCommandType(0x1);
@@ -127,10 +260,74 @@ pub struct LcSegment64 {
 
 #[repr(packed)]
 pub struct LcSymtab {
-	symoff: u32,
-	nsyms: u32,
-	stroff: u32,
-	strsize: u32,
+	pub symoff: u32,
+	pub nsyms: u32,
+	pub stroff: u32,
+	pub strsize: u32,
+}
+
+/// A 32-bit symbol table entry, as found at `LcSymtab.symoff`. Stabs/debug
+/// symbols are flagged by `n_type & 0xe0 != 0` and carry no linkable address.
+#[repr(packed)]
+pub struct Nlist32 {
+	pub n_strx: u32,
+	pub n_type: u8,
+	pub n_sect: u8,
+	pub n_desc: u16,
+	pub n_value: u32,
+}
+
+/// The 64-bit analogue of `Nlist32`, widening only `n_value`.
+#[repr(packed)]
+pub struct Nlist64 {
+	pub n_strx: u32,
+	pub n_type: u8,
+	pub n_sect: u8,
+	pub n_desc: u16,
+	pub n_value: u64,
+}
+
+/// A symbol table entry, sized to match the containing Mach-O.
+pub enum Nlist {
+	N32(Nlist32),
+	N64(Nlist64),
+}
+
+/// Mask selecting the stabs/debug bits of `n_type`; a nonzero result marks a
+/// debug symbol that has no bearing on linking.
+pub const N_STAB: u8 = 0xe0;
+
+impl Nlist {
+	#[inline(always)]
+	pub fn n_strx(&self) -> u32 {
+		match self {
+			&Nlist::N32(ref n) => n.n_strx,
+			&Nlist::N64(ref n) => n.n_strx,
+		}
+	}
+
+	#[inline(always)]
+	pub fn n_type(&self) -> u8 {
+		match self {
+			&Nlist::N32(ref n) => n.n_type,
+			&Nlist::N64(ref n) => n.n_type,
+		}
+	}
+
+	/// The symbol's value, widened to 64 bits regardless of file width.
+	#[inline(always)]
+	pub fn n_value(&self) -> u64 {
+		match self {
+			&Nlist::N32(ref n) => n.n_value as u64,
+			&Nlist::N64(ref n) => n.n_value,
+		}
+	}
+
+	/// True for stabs/debug entries, which are skipped when resolving symbols.
+	#[inline(always)]
+	pub fn is_stab(&self) -> bool {
+		self.n_type() & N_STAB != 0
+	}
 }
 
 #[derive(Copy)]
@@ -201,14 +398,69 @@ pub struct LcUuid {
 
 #[repr(packed)]
 pub struct LcVersionMinOS {
-	version: u32,
-	sdk: u32,
+	pub version: u32,
+	pub sdk: u32,
+}
+
+/// An `xxxx.yy.zz` version, packed into a `u32` as `major:16, minor:8,
+/// patch:8` (the `LC_VERSION_MIN_*` / SDK encoding).
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PackedVersion(pub u32);
+
+impl PackedVersion {
+	#[inline(always)]
+	pub fn major(&self) -> u32 {self.0 >> 16}
+	#[inline(always)]
+	pub fn minor(&self) -> u32 {(self.0 >> 8) & 0xff}
+	#[inline(always)]
+	pub fn patch(&self) -> u32 {self.0 & 0xff}
+}
+
+impl fmt::Display for PackedVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}.{}.{}", self.major(), self.minor(), self.patch())
+	}
+}
+
+impl FromStr for PackedVersion {
+	type Err = ParseIntError;
+	/// Parse `"10.15.0"` (trailing components optional) into its packed form.
+	fn from_str(s: &str) -> Result<PackedVersion, ParseIntError> {
+		let mut it = s.split('.');
+		let major = try!(it.next().unwrap_or("0").parse::<u32>());
+		let minor = try!(it.next().unwrap_or("0").parse::<u32>());
+		let patch = try!(it.next().unwrap_or("0").parse::<u32>());
+		Ok(PackedVersion((major << 16) | (minor << 8) | patch))
+	}
+}
+
+impl LcVersionMinOS {
+	/// The minimum OS version.
+	pub fn version(&self) -> PackedVersion {PackedVersion(self.version)}
+	/// The SDK version the object was built against.
+	pub fn sdk(&self) -> PackedVersion {PackedVersion(self.sdk)}
 }
 
 #[repr(packed)]
 pub struct LcSourceVersion {
 	/// A.B.C.D.E packed as a24.b10.c10.d10.e10
-	version: u64
+	pub version: u64
+}
+
+impl LcSourceVersion {
+	/// The five components of the source version, wide field first.
+	pub fn components(&self) -> (u64, u64, u64, u64, u64) {
+		let v = self.version;
+		(v >> 40, (v >> 30) & 0x3ff, (v >> 20) & 0x3ff,
+			(v >> 10) & 0x3ff, v & 0x3ff)
+	}
+}
+
+impl fmt::Display for LcSourceVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (a, b, c, d, e) = self.components();
+		write!(f, "{}.{}.{}.{}.{}", a, b, c, d, e)
+	}
 }
 
 use std::marker::MarkerTrait;
@@ -270,14 +522,73 @@ pub struct Section64 {
 	pub reserved3: u32,
 }
 
-/// A complete Mach-O segment, including any trailing sections and file data.
+/// High bit of a relocation's first word, flagging the scattered form.
+pub const R_SCATTERED: u32 = 0x80000000;
+
+/// A decoded Mach-O `relocation_info` (or `scattered_relocation_info`). Both
+/// on-disk forms are eight bytes; `scattered` records which was read, and
+/// `r_value` is meaningful only for the scattered form.
+pub struct RelocationInfo {
+	pub r_address: i32,
+	pub r_symbolnum: u32,
+	pub r_pcrel: bool,
+	pub r_length: u8,
+	pub r_extern: bool,
+	pub r_type: u8,
+	pub scattered: bool,
+	pub r_value: i32,
+}
+
+impl RelocationInfo {
+	/// Number of bytes the relocation patches, derived from `r_length`.
+	#[inline(always)]
+	pub fn byte_len(&self) -> usize {
+		1 << self.r_length
+	}
+
+	/// Decode one eight-byte relocation entry, little-endian on disk. The
+	/// length check keeps a lying `reloff`/`nreloc` from panicking the reader.
+	pub fn decode(buf: &[u8]) -> Result<RelocationInfo, Error> {
+		if buf.len() < 8 {return Err(TooShort)};
+		let w0 = (buf[0] as u32) | (buf[1] as u32) << 8
+			| (buf[2] as u32) << 16 | (buf[3] as u32) << 24;
+		let w1 = (buf[4] as u32) | (buf[5] as u32) << 8
+			| (buf[6] as u32) << 16 | (buf[7] as u32) << 24;
+		if w0 & R_SCATTERED != 0 {
+			Ok(RelocationInfo {
+				r_address: (w0 & 0x00ffffff) as i32,
+				r_symbolnum: 0,
+				r_type: ((w0 >> 24) & 0xf) as u8,
+				r_length: ((w0 >> 28) & 0x3) as u8,
+				r_pcrel: (w0 >> 30) & 0x1 != 0,
+				r_extern: false,
+				scattered: true,
+				r_value: w1 as i32,
+			})
+		} else {
+			Ok(RelocationInfo {
+				r_address: w0 as i32,
+				r_symbolnum: w1 & 0x00ffffff,
+				r_pcrel: (w1 >> 24) & 0x1 != 0,
+				r_length: ((w1 >> 25) & 0x3) as u8,
+				r_extern: (w1 >> 27) & 0x1 != 0,
+				r_type: ((w1 >> 28) & 0xf) as u8,
+				scattered: false,
+				r_value: 0,
+			})
+		}
+	}
+}
+
+/// A complete Mach-O segment, including any trailing sections, file data, and
+/// per-section relocations.
 pub enum LC {
-	Segment32(LoadCommand<LcSegment32>, Vec<(Section32, Vec<u8>)>),
-	Symtab(LoadCommand<LcSymtab>),
+	Segment32(LoadCommand<LcSegment32>, Vec<(Section32, Vec<u8>, Vec<RelocationInfo>)>),
+	Symtab(LoadCommand<LcSymtab>, Vec<(String, Nlist)>),
 	UnixThread(LoadCommand<LcUnixThreadHead>, ThreadState),
 	DySymtab(LoadCommand<LcDySymtab>),
 	LoadDylinker(LoadCommand<LcLoadDylinker>, Vec<u8>),
-	Segment64(LoadCommand<LcSegment64>, Vec<(Section64, Vec<u8>)>),
+	Segment64(LoadCommand<LcSegment64>, Vec<(Section64, Vec<u8>, Vec<RelocationInfo>)>),
 	Uuid(LoadCommand<LcUuid>),
 	VersionMinOS(LoadCommand<LcVersionMinOS>),
 	SourceVersion(LoadCommand<LcSourceVersion>),
@@ -298,3 +609,484 @@ impl MachO {
 		})
 	}
 }
+
+impl FromBytes for Header {
+	const SIZE: usize = 28;
+	fn read_from(buf: &[u8], e: Endian) -> Result<Header, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(Header {
+			magic: Magic(try!(r.u32())),
+			cputype: try!(r.u32()),
+			cpusubtype: try!(r.u32()),
+			filetype: try!(r.u32()),
+			ncmds: try!(r.u32()),
+			sizeofcmds: try!(r.u32()),
+			flags: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for Header64 {
+	const SIZE: usize = 32;
+	fn read_from(buf: &[u8], e: Endian) -> Result<Header64, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(Header64 {
+			magic: Magic(try!(r.u32())),
+			cputype: try!(r.u32()),
+			cpusubtype: try!(r.u32()),
+			filetype: try!(r.u32()),
+			ncmds: try!(r.u32()),
+			sizeofcmds: try!(r.u32()),
+			flags: try!(r.u32()),
+			reserved: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for LoadCommandHead {
+	const SIZE: usize = 8;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LoadCommandHead, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LoadCommandHead {
+			cmd: LoadCommandType(try!(r.u32())),
+			cmdsize: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for LcSegment32 {
+	const SIZE: usize = 48;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcSegment32, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcSegment32 {
+			segname: try!(r.bytes16()),
+			vmaddr: try!(r.u32()),
+			vmsize: try!(r.u32()),
+			fileoff: try!(r.u32()),
+			filesize: try!(r.u32()),
+			maxprot: try!(r.u32()),
+			initprot: try!(r.u32()),
+			nsects: try!(r.u32()),
+			flags: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for LcSegment64 {
+	const SIZE: usize = 64;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcSegment64, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcSegment64 {
+			segname: try!(r.bytes16()),
+			vmaddr: try!(r.u64()),
+			vmsize: try!(r.u64()),
+			fileoff: try!(r.u64()),
+			filesize: try!(r.u64()),
+			maxprot: try!(r.u32()),
+			initprot: try!(r.u32()),
+			nsects: try!(r.u32()),
+			flags: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for Section32 {
+	const SIZE: usize = 68;
+	fn read_from(buf: &[u8], e: Endian) -> Result<Section32, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(Section32 {
+			sectname: try!(r.bytes16()),
+			segname: try!(r.bytes16()),
+			addr: try!(r.u32()),
+			size: try!(r.u32()),
+			offset: try!(r.u32()),
+			align: try!(r.u32()),
+			reloff: try!(r.u32()),
+			nreloc: try!(r.u32()),
+			flags: try!(r.u32()),
+			reserved1: try!(r.u32()),
+			reserved2: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for Section64 {
+	const SIZE: usize = 80;
+	fn read_from(buf: &[u8], e: Endian) -> Result<Section64, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(Section64 {
+			sectname: try!(r.bytes16()),
+			segname: try!(r.bytes16()),
+			addr: try!(r.u64()),
+			size: try!(r.u64()),
+			offset: try!(r.u32()),
+			align: try!(r.u32()),
+			reloff: try!(r.u32()),
+			nreloc: try!(r.u32()),
+			flags: try!(r.u32()),
+			reserved1: try!(r.u32()),
+			reserved2: try!(r.u32()),
+			reserved3: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for LcSymtab {
+	const SIZE: usize = 16;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcSymtab, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcSymtab {
+			symoff: try!(r.u32()),
+			nsyms: try!(r.u32()),
+			stroff: try!(r.u32()),
+			strsize: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for LcDySymtab {
+	const SIZE: usize = 72;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcDySymtab, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcDySymtab {
+			ilocalsym: try!(r.u32()),
+			nlocalsym: try!(r.u32()),
+			iextdefsym: try!(r.u32()),
+			nextdefsym: try!(r.u32()),
+			iundefsym: try!(r.u32()),
+			nundefsym: try!(r.u32()),
+			tocoff: try!(r.u32()),
+			ntoc: try!(r.u32()),
+			modtaboff: try!(r.u32()),
+			nmodtab: try!(r.u32()),
+			extrefsymoff: try!(r.u32()),
+			nextrefsyms: try!(r.u32()),
+			indirectsymoff: try!(r.u32()),
+			nindirectsyms: try!(r.u32()),
+			extreloff: try!(r.u32()),
+			nextrel: try!(r.u32()),
+			locreloff: try!(r.u32()),
+			nlocrel: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for LcUuid {
+	const SIZE: usize = 16;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcUuid, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcUuid {uuid: try!(r.bytes16())})
+	}
+}
+
+impl FromBytes for LcVersionMinOS {
+	const SIZE: usize = 8;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcVersionMinOS, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcVersionMinOS {version: try!(r.u32()), sdk: try!(r.u32())})
+	}
+}
+
+impl FromBytes for LcSourceVersion {
+	const SIZE: usize = 8;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcSourceVersion, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcSourceVersion {version: try!(r.u64())})
+	}
+}
+
+impl FromBytes for LcUnixThreadHead {
+	const SIZE: usize = 8;
+	fn read_from(buf: &[u8], e: Endian) -> Result<LcUnixThreadHead, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(LcUnixThreadHead {
+			flavor: ThreadStateFlavor(try!(r.u32())),
+			count: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for Nlist32 {
+	const SIZE: usize = 12;
+	fn read_from(buf: &[u8], e: Endian) -> Result<Nlist32, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(Nlist32 {
+			n_strx: try!(r.u32()),
+			n_type: try!(r.u8()),
+			n_sect: try!(r.u8()),
+			n_desc: try!(r.u16()),
+			n_value: try!(r.u32()),
+		})
+	}
+}
+
+impl FromBytes for Nlist64 {
+	const SIZE: usize = 16;
+	fn read_from(buf: &[u8], e: Endian) -> Result<Nlist64, Error> {
+		let mut r = Reader::new(buf, e);
+		Ok(Nlist64 {
+			n_strx: try!(r.u32()),
+			n_type: try!(r.u8()),
+			n_sect: try!(r.u8()),
+			n_desc: try!(r.u16()),
+			n_value: try!(r.u64()),
+		})
+	}
+}
+
+/// A growable byte sink that serializes integers in a chosen endianness, the
+/// mirror of `Reader` used when re-emitting a Mach-O.
+struct Writer {
+	buf: Vec<u8>,
+	endian: Endian,
+}
+
+impl Writer {
+	fn new(endian: Endian) -> Writer {
+		Writer {buf: Vec::new(), endian: endian}
+	}
+
+	fn u8(&mut self, v: u8) {self.buf.push(v)}
+
+	fn u16(&mut self, v: u16) {
+		match self.endian {
+			Endian::Little => {self.u8(v as u8); self.u8((v >> 8) as u8)},
+			Endian::Big    => {self.u8((v >> 8) as u8); self.u8(v as u8)},
+		}
+	}
+
+	fn u32(&mut self, v: u32) {
+		match self.endian {
+			Endian::Little => {self.u16(v as u16); self.u16((v >> 16) as u16)},
+			Endian::Big    => {self.u16((v >> 16) as u16); self.u16(v as u16)},
+		}
+	}
+
+	fn u64(&mut self, v: u64) {
+		match self.endian {
+			Endian::Little => {self.u32(v as u32); self.u32((v >> 32) as u32)},
+			Endian::Big    => {self.u32((v >> 32) as u32); self.u32(v as u32)},
+		}
+	}
+
+	fn bytes(&mut self, v: &[u8]) {self.buf.extend(v.iter().cloned())}
+}
+
+/// A pointer-width-agnostic view of a section, letting one emission path cover
+/// both `Section32` and `Section64`.
+struct SectionView<'a> {
+	sectname: U8N16,
+	segname: U8N16,
+	addr: u64,
+	align: u32,
+	flags: u32,
+	reserved1: u32,
+	reserved2: u32,
+	reserved3: u32,
+	data: &'a [u8],
+}
+
+impl MachO {
+	/// Serialize this Mach-O back to bytes, recomputing `ncmds`,
+	/// `sizeofcmds`, each `cmdsize`, and section `offset`/`fileoff` so the
+	/// result is internally consistent. A single code path handles 32- and
+	/// 64-bit segments by widening 32-bit fields into a `SectionView`.
+	///
+	/// The writer emits segment section data only: it does not carry a
+	/// `__LINKEDIT` region, so the symbol/string tables and per-section
+	/// relocations are dropped and their `Symtab` offsets and section
+	/// `reloff`/`nreloc` are zeroed to keep the file internally consistent.
+	/// It therefore round-trips stripped, section-data-only objects, not
+	/// binaries whose load commands reference a linkedit blob.
+	pub fn write_to<W: Write>(&self, mut sink: W) -> io::Result<()> {
+		let wide = self.header.magic == M64;
+		let endian = Endian::Little;
+
+		// Sizes of the fixed parts, by pointer width.
+		let hdr_size = if wide {
+			<Header64 as FromBytes>::SIZE
+		} else {
+			<Header as FromBytes>::SIZE
+		};
+		let seg_body = if wide {
+			<LcSegment64 as FromBytes>::SIZE
+		} else {
+			<LcSegment32 as FromBytes>::SIZE
+		};
+		let sect_size = if wide {
+			<Section64 as FromBytes>::SIZE
+		} else {
+			<Section32 as FromBytes>::SIZE
+		};
+		let head_size = <LoadCommandHead as FromBytes>::SIZE;
+
+		// First pass: total bytes occupied by the load commands.
+		let sizeofcmds: usize = self.loads.iter().map(|lc| self.cmdsize(lc,
+			head_size, seg_body, sect_size)).sum();
+
+		// Section payloads are laid down immediately after the commands.
+		let mut cursor = hdr_size + sizeofcmds;
+
+		let mut cmds = Writer::new(endian);
+		let mut payload: Vec<u8> = Vec::new();
+
+		for lc in self.loads.iter() {
+			match lc {
+				&LC::Segment32(ref c, ref sects) => {
+					let views = sects.iter().map(|&(ref s, ref d, _)| SectionView {
+						sectname: s.sectname, segname: s.segname,
+						addr: s.addr as u64, align: s.align, flags: s.flags,
+						reserved1: s.reserved1, reserved2: s.reserved2,
+						reserved3: 0, data: &d[..],
+					}).collect::<Vec<_>>();
+					self.emit_segment(&mut cmds, &mut payload, &mut cursor,
+						false, c.body.segname, c.body.vmaddr as u64,
+						c.body.vmsize as u64, c.body.maxprot, c.body.initprot,
+						c.body.flags, head_size, seg_body, sect_size, &views[..]);
+				},
+				&LC::Segment64(ref c, ref sects) => {
+					let views = sects.iter().map(|&(ref s, ref d, _)| SectionView {
+						sectname: s.sectname, segname: s.segname,
+						addr: s.addr, align: s.align, flags: s.flags,
+						reserved1: s.reserved1, reserved2: s.reserved2,
+						reserved3: s.reserved3, data: &d[..],
+					}).collect::<Vec<_>>();
+					self.emit_segment(&mut cmds, &mut payload, &mut cursor,
+						true, c.body.segname, c.body.vmaddr, c.body.vmsize,
+						c.body.maxprot, c.body.initprot, c.body.flags,
+						head_size, seg_body, sect_size, &views[..]);
+				},
+				&LC::Symtab(..) => {
+					// The symbol and string tables live in __LINKEDIT, which
+					// this writer does not emit; zero the offsets so the command
+					// does not dangle past the end of the re-emitted file.
+					cmds.u32(Symtab.0);
+					cmds.u32((head_size + <LcSymtab as FromBytes>::SIZE) as u32);
+					cmds.u32(0); cmds.u32(0);
+					cmds.u32(0); cmds.u32(0);
+				},
+				&LC::DySymtab(ref c) => {
+					cmds.u32(DySymtab.0);
+					cmds.u32((head_size + <LcDySymtab as FromBytes>::SIZE) as u32);
+					let b = &c.body;
+					for w in [b.ilocalsym, b.nlocalsym, b.iextdefsym, b.nextdefsym,
+						b.iundefsym, b.nundefsym, b.tocoff, b.ntoc, b.modtaboff,
+						b.nmodtab, b.extrefsymoff, b.nextrefsyms, b.indirectsymoff,
+						b.nindirectsyms, b.extreloff, b.nextrel, b.locreloff,
+						b.nlocrel].iter() {cmds.u32(*w)};
+				},
+				&LC::Uuid(ref c) => {
+					cmds.u32(Uuid.0);
+					cmds.u32((head_size + <LcUuid as FromBytes>::SIZE) as u32);
+					cmds.bytes(&c.body.uuid);
+				},
+				&LC::VersionMinOS(ref c) => {
+					cmds.u32(VersionMinOS.0);
+					cmds.u32((head_size + <LcVersionMinOS as FromBytes>::SIZE) as u32);
+					cmds.u32(c.body.version); cmds.u32(c.body.sdk);
+				},
+				&LC::SourceVersion(ref c) => {
+					cmds.u32(SourceVersion.0);
+					cmds.u32((head_size + <LcSourceVersion as FromBytes>::SIZE) as u32);
+					cmds.u64(c.body.version);
+				},
+				&LC::UnixThread(ref c, ref ts) => {
+					cmds.u32(UnixThread.0);
+					cmds.u32(c.head.cmdsize);
+					cmds.u32(c.body.flavor.0); cmds.u32(c.body.count);
+					match ts {
+						&ThreadState::ThreadStateX86(ref regs) =>
+							for r in regs.iter() {cmds.u32(*r)},
+						&ThreadState::ThreadStateX86_64(ref regs) =>
+							for r in regs.iter() {cmds.u64(*r)},
+					}
+				},
+				// A dynamic linker command has no place in a re-emitted static
+				// file; `cmdsize` counts it as zero bytes in `sizeofcmds` above,
+				// and it is excluded from `ncmds` below.
+				&LC::LoadDylinker(..) => {},
+			}
+		}
+
+		// Header with recomputed counts, then commands, then section data.
+		// Dynamic linker commands are not emitted, so they do not count.
+		let ncmds = self.loads.iter()
+			.filter(|lc| match **lc {LC::LoadDylinker(..) => false, _ => true})
+			.count();
+		let mut hdr = Writer::new(endian);
+		hdr.u32(self.header.magic.0);
+		hdr.u32(self.header.cputype);
+		hdr.u32(self.header.cpusubtype);
+		hdr.u32(self.header.filetype);
+		hdr.u32(ncmds as u32);
+		hdr.u32(sizeofcmds as u32);
+		hdr.u32(self.header.flags);
+		if wide {hdr.u32(0)};
+
+		try!(sink.write_all(&hdr.buf[..]));
+		try!(sink.write_all(&cmds.buf[..]));
+		try!(sink.write_all(&payload[..]));
+		Ok(())
+	}
+
+	/// The on-disk `cmdsize` of one load command.
+	fn cmdsize(&self, lc: &LC, head: usize, seg_body: usize, sect: usize)
+	-> usize {
+		match lc {
+			&LC::Segment32(_, ref s) => head + seg_body + sect*s.len(),
+			&LC::Segment64(_, ref s) => head + seg_body + sect*s.len(),
+			&LC::Symtab(..) => head + <LcSymtab as FromBytes>::SIZE,
+			&LC::DySymtab(_) => head + <LcDySymtab as FromBytes>::SIZE,
+			&LC::Uuid(_) => head + <LcUuid as FromBytes>::SIZE,
+			&LC::VersionMinOS(_) => head + <LcVersionMinOS as FromBytes>::SIZE,
+			&LC::SourceVersion(_) => head + <LcSourceVersion as FromBytes>::SIZE,
+			&LC::UnixThread(ref c, _) => c.head.cmdsize as usize,
+			&LC::LoadDylinker(..) => 0,
+		}
+	}
+
+	/// Emit one segment load command and queue its section payloads, assigning
+	/// each section a fresh file offset drawn from `cursor`.
+	fn emit_segment(&self, cmds: &mut Writer, payload: &mut Vec<u8>,
+		cursor: &mut usize, wide: bool, segname: U8N16, vmaddr: u64,
+		vmsize: u64, maxprot: VmProt, initprot: VmProt, flags: u32,
+		head: usize, seg_body: usize, sect: usize, views: &[SectionView]) {
+		let cmdsize = head + seg_body + sect*views.len();
+		let fileoff = *cursor;
+		let filesize: usize = views.iter().map(|v| v.data.len()).sum();
+
+		cmds.u32(if wide {Segment64.0} else {Segment32.0});
+		cmds.u32(cmdsize as u32);
+		cmds.bytes(&segname);
+		if wide {
+			cmds.u64(vmaddr); cmds.u64(vmsize);
+			cmds.u64(fileoff as u64); cmds.u64(filesize as u64);
+		} else {
+			cmds.u32(vmaddr as u32); cmds.u32(vmsize as u32);
+			cmds.u32(fileoff as u32); cmds.u32(filesize as u32);
+		}
+		cmds.u32(maxprot); cmds.u32(initprot);
+		cmds.u32(views.len() as u32); cmds.u32(flags);
+
+		for v in views.iter() {
+			let off = *cursor;
+			cmds.bytes(&v.sectname);
+			cmds.bytes(&v.segname);
+			if wide {
+				cmds.u64(v.addr); cmds.u64(v.data.len() as u64);
+			} else {
+				cmds.u32(v.addr as u32); cmds.u32(v.data.len() as u32);
+			}
+			cmds.u32(off as u32);
+			cmds.u32(v.align);
+			// Relocations are not re-emitted; a rewritten file carries none.
+			cmds.u32(0); cmds.u32(0);
+			cmds.u32(v.flags);
+			cmds.u32(v.reserved1); cmds.u32(v.reserved2);
+			if wide {cmds.u32(v.reserved3)};
+
+			payload.extend(v.data.iter().cloned());
+			*cursor += v.data.len();
+		}
+	}
+}
@@ -10,10 +10,11 @@
 //!
 //! ## Limitations
 //!
-//! Where to begin. For one, ld9 assumes the entry point is the first
-//! symbol in the TEXT. It assumes that the TEXT can/will be loaded to 0x1000,
+//! Where to begin. It assumes that the TEXT can/will be loaded to 0x1000,
 //! despite the fact that by default static Mach-O's are compiled to load their
-//! text to `DATA - size(TEXT)`; hope it's position-independent.
+//! text to `DATA - size(TEXT)`; the relocations recorded in each `__TEXT`
+//! section are applied to rebase the text accordingly. Relocations recorded in
+//! `__DATA` sections are not applied.
 //!
 //! It also assumes that the TEXT will find the DATA wherever Plan 9 loads it;
 //! this actually is a workable assumption, since Mac loads it to 0x2000
@@ -24,11 +25,10 @@
 //! executables, since by design Plan 9 does not support dynamic linking.
 
 #![allow(non_snake_case, non_upper_case_globals)]
-#![feature(core, io, fs)]
+#![feature(core, io, fs, associated_consts)]
 
 extern crate byteorder;
 
-use std::mem::size_of;
 use std::num::ToPrimitive;
 use std::iter::AdditiveIterator;
 use std::borrow::ToOwned;
@@ -47,22 +47,163 @@ pub enum Error {
 	UnrecognizedSegment(usize, u32),
 	UnrecognizedThreadState(u32, u32),
 	DynamicUnsupported,
+	NoEntrySymbol,
+	UnsupportedReloc(u8),
+	NoMatchingArch(u32),
+	BadSymbolIndex(u32),
 }
 use Error::*;
 
-/// Treat a struct as an array of bytes.
-// unsafe fn as_bytes<'a, T>(data: &'a mut T) -> &'a mut [u8] {
-// 	std::slice::from_raw_parts_mut(data as *mut T as *mut u8,
-// 		size_of::<T>())
-// }
-
-/// Reinterprets a slice as T.
-/// Undefined behavior if slice is shorter than size of T.
-unsafe fn reinterpret_copy<T>(data: &[u8]) -> T {
-	let (rs, _) = std::mem::transmute::<&[u8],(&T, usize)>(data);
-	std::mem::transmute_copy::<T, T>(rs)
+/// Read a big-endian 32-bit word at `off`.
+fn be32(buf: &[u8], off: usize) -> u32 {
+	(buf[off] as u32) << 24 | (buf[off+1] as u32) << 16
+		| (buf[off+2] as u32) << 8 | (buf[off+3] as u32)
 }
 
+/// Read a big-endian 64-bit word at `off`.
+fn be64(buf: &[u8], off: usize) -> u64 {
+	(be32(buf, off) as u64) << 32 | (be32(buf, off+4) as u64)
+}
+
+/// Pick the sub-slice for the requested architecture out of a fat (universal)
+/// Mach-O. Thin files are passed through unchanged, so callers may invoke this
+/// unconditionally before `decode_macho`.
+fn select_arch(file: &[u8], want: macho::CpuType) -> Result<&[u8], Error> {
+	if file.len() < 8 {return Err(TooShort)};
+
+	let wide = match be32(file, 0) {
+		macho::FAT_MAGIC => false,
+		macho::FAT_MAGIC_64 => true,
+		_ => return Ok(file),
+	};
+
+	let len = file.len();
+	let want = want as u32;
+	let nfat = be32(file, 4) as usize;
+	let arch_size = if wide {32} else {20};
+	let mut off = 8;
+	for _ in range(0, nfat) {
+		// Each arch entry must lie wholly within the file before we read it; a
+		// truncated or lying fat header returns an error instead of panicking.
+		if off + arch_size > len {return Err(TooShort)};
+		let cputype = be32(file, off);
+		let (foff, fsize) = if wide {
+			(be64(file, off+8) as usize, be64(file, off+16) as usize)
+		} else {
+			(be32(file, off+8) as usize, be32(file, off+12) as usize)
+		};
+		if cputype == want {
+			if foff.checked_add(fsize).map_or(true, |end| end > len) {
+				return Err(TooShort);
+			}
+			return Ok(&file[foff..foff+fsize]);
+		}
+		off += arch_size;
+	}
+	Err(NoMatchingArch(want))
+}
+
+/// Decode the `nreloc` relocation entries a section points at via `reloff`.
+fn decode_relocs(file: &[u8], reloff: u32, nreloc: u32)
+-> Result<Vec<macho::RelocationInfo>, Error> {
+	use macho::RelocationInfo;
+	let base = reloff as usize;
+	let n = nreloc as usize;
+	// Reject a table that runs past the file before touching its bytes. The
+	// multiply is checked too, so a huge `nreloc` cannot overflow usize first.
+	if 8usize.checked_mul(n).and_then(|w| base.checked_add(w))
+		.map_or(true, |end| end > file.len()) {
+		return Err(TooShort);
+	}
+	let mut relocs = Vec::with_capacity(n);
+	for i in range(0, n) {
+		relocs.push(try!(RelocationInfo::decode(&file[base + 8*i ..])));
+	}
+	Ok(relocs)
+}
+
+/// The Plan 9 virtual address ld9 loads the text segment to.
+const PLAN9_TEXT_BASE: u64 = 0x1000;
+
+/// Patch one section's worth of text, given its `base` offset within the
+/// concatenated text buffer. Extern relocations resolve against `syms`; local
+/// ones are shifted by the delta between the Plan 9 base and the Mach-O
+/// `vmaddr`. PC-relative entries subtract the patched location's own address.
+fn apply_relocs(buf: &mut [u8], base: usize,
+	relocs: &[macho::RelocationInfo],
+	syms: Option<&Vec<(String, macho::Nlist)>>,
+	vmaddr: u64, cputype: u32) -> Result<(), Error> {
+	use macho::CpuType;
+	// The generic (x86) and x86-64 reloc type numberings differ, so the set of
+	// types we know how to patch depends on the source CPU. GOT and
+	// thread-local forms need indirection tables we do not build; refuse any
+	// type outside this set rather than emit a wrong patch.
+	let supported: &[u8] = match cputype {
+		c if c == CpuType::X86_64 as u32 => &[0, 1, 2],
+		c if c == CpuType::X86 as u32 => &[0],
+		_ => &[],
+	};
+	for r in relocs.iter() {
+		if !supported.contains(&r.r_type) {
+			return Err(UnsupportedReloc(r.r_type));
+		}
+
+		let at = base + r.r_address as usize;
+		let len = r.byte_len();
+		// A lying `r_address` must not index past the concatenated text.
+		if at.checked_add(len).map_or(true, |end| end > buf.len()) {
+			return Err(TooShort);
+		}
+		let mut value = 0u64;
+		for k in range(0, len) {
+			value |= (buf[at + k] as u64) << (8*k);
+		}
+
+		// Rebasing arithmetic can legitimately cross zero for a byte treated as
+		// an unsigned field; wrap deliberately so a section based above the
+		// Plan 9 load address does not panic in debug builds.
+		let target = if r.r_extern {
+			let nl = syms.and_then(|s| s.get(r.r_symbolnum as usize));
+			match nl {
+				Some(&(_, ref nl)) => value
+					.wrapping_add(nl.n_value())
+					.wrapping_sub(vmaddr)
+					.wrapping_add(PLAN9_TEXT_BASE),
+				// A reloc referencing a symbol outside the table is a bad
+				// symbol index, not an entry-point or reloc-type failure.
+				None => return Err(BadSymbolIndex(r.r_symbolnum)),
+			}
+		} else {
+			value.wrapping_add(PLAN9_TEXT_BASE).wrapping_sub(vmaddr)
+		};
+
+		let target = if r.r_pcrel {
+			target.wrapping_sub(PLAN9_TEXT_BASE + at as u64)
+		} else {
+			target
+		};
+
+		for k in range(0, len) {
+			buf[at + k] = (target >> (8*k)) as u8;
+		}
+	}
+	Ok(())
+}
+
+/// Read a NUL-terminated string out of a string blob, starting at `off`.
+fn read_cstr(blob: &[u8], off: usize) -> String {
+	// `n_strx` is an untrusted field; an out-of-range index just names the
+	// empty string rather than panicking the decoder.
+	if off >= blob.len() {return String::new()};
+	let end = blob[off..].iter().position(|&b| b == 0)
+		.map(|p| off + p).unwrap_or(blob.len());
+	String::from_utf8_lossy(&blob[off..end]).into_owned()
+}
+
+/// The symbol names ld9 recognizes as a program's entry point, in priority
+/// order.
+static ENTRY_SYMBOLS: [&'static str; 3] = ["start", "_start", "_main"];
+
 /// Load a Mach-O file into memory.
 fn load<T: ToPrimitive, U: ToPrimitive>(file: &[u8], offset: T, size: U)
 -> Vec<u8> {
@@ -74,14 +215,15 @@ fn load<T: ToPrimitive, U: ToPrimitive>(file: &[u8], offset: T, size: U)
 fn decode_macho(file: &[u8]) -> Result<MachO, Error> {
 	use macho::*;
 
+	let endian = try!(endian_of(file));
+
 	let mut offset = 0;
-	if file.len() < 4 {return Err(TooShort)}
 
-	let h: Header = unsafe {reinterpret_copy(&file[..])};
+	let h = try!(Header::read_from(file, endian));
 
 	offset += match h.magic {
-		M32 => size_of::<Header>(),
-		M64 => size_of::<Header64>(),
+		M32 => <Header as FromBytes>::SIZE,
+		M64 => <Header64 as FromBytes>::SIZE,
 		_ => return Err(UnknownMagic(h.magic.0))
 	};
 
@@ -89,92 +231,138 @@ fn decode_macho(file: &[u8]) -> Result<MachO, Error> {
 
 	let mut l = Vec::with_capacity(h.ncmds as usize);
 	for i in range(0, h.ncmds as usize) {
-		let lch: LoadCommandHead = unsafe {reinterpret_copy(&file[offset..])};
+		// `cmdsize` is untrusted and advances `offset`; a single oversized
+		// command must not push the next slice past the end of the file.
+		if offset > file.len() {return Err(TooShort)};
+		let lch = try!(LoadCommandHead::read_from(&file[offset..], endian));
+		let body = offset + <LoadCommandHead as FromBytes>::SIZE;
 		let cmd_size = lch.cmdsize as usize;
 		let seg: Result<LC, Error> = match lch.cmd {
 			Segment32 => {
-				println!("seg {}", cmd_size);
-				let lc_size = size_of::<LoadCommand<LcSegment32>>();
-				let lc: LoadCommand<LcSegment32> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				let nsects = lc.body.nsects as usize;
-				let section_size = size_of::<Section32>();
+				let lc_size = <LoadCommandHead as FromBytes>::SIZE
+					+ <LcSegment32 as FromBytes>::SIZE;
+				let segcmd = try!(LcSegment32::read_from(&file[body..], endian));
+				let nsects = segcmd.nsects as usize;
+				let section_size = <Section32 as FromBytes>::SIZE;
 				let est_size = lc_size + section_size*nsects;
 				if est_size != cmd_size {
-					println!("{} vs {}", est_size, cmd_size);
 					Err(SizeMismatch)
 				} else {
 					let mut sections = Vec::with_capacity(nsects);
 					for j in range(0, nsects) {
-						let sect: Section32 = unsafe {reinterpret_copy(
-							&file[offset + lc_size + section_size*j ..])};
+						let sect = try!(Section32::read_from(
+							&file[offset + lc_size + section_size*j ..], endian));
+						// The section payload lives at `sect.offset`, outside the
+						// load-command region `est_size` covers; bound it against
+						// the file before slicing so a crafted section header
+						// cannot panic the decoder.
+						if (sect.offset as usize).checked_add(sect.size as usize)
+							.map_or(true, |end| end > file.len()) {
+							return Err(TooShort);
+						}
 						let data = load(file, sect.offset, sect.size);
-						sections.push((sect, data));
+						let relocs = try!(decode_relocs(file, sect.reloff, sect.nreloc));
+						sections.push((sect, data, relocs));
 					}
-					Ok(LC::Segment32(lc, sections))
+					Ok(LC::Segment32(LoadCommand {head: lch, body: segcmd},
+						sections))
 				}
 			},
 			Segment64 => {
-				println!("seg {}", cmd_size);
-				let lc_size = size_of::<LoadCommand<LcSegment64>>();
-				let lc: LoadCommand<LcSegment64> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				let nsects = lc.body.nsects as usize;
-				let section_size = size_of::<Section64>();
+				let lc_size = <LoadCommandHead as FromBytes>::SIZE
+					+ <LcSegment64 as FromBytes>::SIZE;
+				let segcmd = try!(LcSegment64::read_from(&file[body..], endian));
+				let nsects = segcmd.nsects as usize;
+				let section_size = <Section64 as FromBytes>::SIZE;
 				let est_size = lc_size + section_size*nsects;
 				if est_size != cmd_size {
-					println!("{} vs {}", est_size, cmd_size);
 					Err(SizeMismatch)
 				} else {
 					let mut sections = Vec::with_capacity(nsects);
 					for j in range(0, nsects) {
-						let sect: Section64 = unsafe {reinterpret_copy(
-							&file[offset + lc_size + section_size*j ..])};
+						let sect = try!(Section64::read_from(
+							&file[offset + lc_size + section_size*j ..], endian));
+						// The section payload lives at `sect.offset`, outside the
+						// load-command region `est_size` covers; bound it against
+						// the file before slicing so a crafted section header
+						// cannot panic the decoder.
+						if (sect.offset as usize).checked_add(sect.size as usize)
+							.map_or(true, |end| end > file.len()) {
+							return Err(TooShort);
+						}
 						let data = load(file, sect.offset, sect.size);
-						sections.push((sect, data));
+						let relocs = try!(decode_relocs(file, sect.reloff, sect.nreloc));
+						sections.push((sect, data, relocs));
 					}
-					Ok(LC::Segment64(lc, sections))
+					Ok(LC::Segment64(LoadCommand {head: lch, body: segcmd},
+						sections))
 				}
 			},
 			Symtab => {
-				let lc: LoadCommand<LcSymtab> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				Ok(LC::Symtab(lc))
+				let tab = try!(LcSymtab::read_from(&file[body..], endian));
+				let nsyms = tab.nsyms as usize;
+				let symoff = tab.symoff as usize;
+				let stroff = tab.stroff as usize;
+				let nl_size = match h.magic {
+					M64 => <Nlist64 as FromBytes>::SIZE,
+					_ => <Nlist32 as FromBytes>::SIZE,
+				};
+				// Validate both tables against the file before indexing; a
+				// crafted symoff/nsyms/strsize must not panic the decoder.
+				if stroff.checked_add(tab.strsize as usize)
+					.map_or(true, |end| end > file.len()) {
+					return Err(TooShort);
+				}
+				if nl_size.checked_mul(nsyms).and_then(|w| symoff.checked_add(w))
+					.map_or(true, |end| end > file.len()) {
+					return Err(TooShort);
+				}
+				let strs = load(file, stroff, tab.strsize);
+				let mut syms = Vec::with_capacity(nsyms);
+				for j in range(0, nsyms) {
+					let at = &file[symoff + nl_size*j ..];
+					let nl = match h.magic {
+						M64 => Nlist::N64(try!(Nlist64::read_from(at, endian))),
+						_ => Nlist::N32(try!(Nlist32::read_from(at, endian))),
+					};
+					let name = read_cstr(&strs[..], nl.n_strx() as usize);
+					syms.push((name, nl));
+				}
+				Ok(LC::Symtab(LoadCommand {head: lch, body: tab}, syms))
 			},
 			DySymtab => {
-				let lc: LoadCommand<LcDySymtab> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				Ok(LC::DySymtab(lc))
+				let b = try!(LcDySymtab::read_from(&file[body..], endian));
+				Ok(LC::DySymtab(LoadCommand {head: lch, body: b}))
 			},
 			Uuid => {
-				let lc: LoadCommand<LcUuid> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				Ok(LC::Uuid(lc))
+				let b = try!(LcUuid::read_from(&file[body..], endian));
+				Ok(LC::Uuid(LoadCommand {head: lch, body: b}))
 			},
 			VersionMinOS => {
-				let lc: LoadCommand<LcVersionMinOS> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				Ok(LC::VersionMinOS(lc))
+				let b = try!(LcVersionMinOS::read_from(&file[body..], endian));
+				Ok(LC::VersionMinOS(LoadCommand {head: lch, body: b}))
 			},
 			SourceVersion => {
-				let lc: LoadCommand<LcSourceVersion> = unsafe {reinterpret_copy(
-					&file[offset..])};
-				Ok(LC::SourceVersion(lc))
+				let b = try!(LcSourceVersion::read_from(&file[body..], endian));
+				Ok(LC::SourceVersion(LoadCommand {head: lch, body: b}))
 			},
 			UnixThread => {
-				let lc: LoadCommand<LcUnixThreadHead>
-					= unsafe {reinterpret_copy(&file[offset..])};
-				let lc_size = size_of::<LoadCommand<LcUnixThreadHead>>();
-				match (lc.body.flavor, lc.body.count) {
+				let th = try!(LcUnixThreadHead::read_from(&file[body..], endian));
+				let ts_off = body + <LcUnixThreadHead as FromBytes>::SIZE;
+				match (th.flavor, th.count) {
 					(ThreadStateFlavorX86, 16) => {
-						let ts = ThreadState::ThreadStateX86(
-							unsafe {reinterpret_copy(&file[offset+lc_size..])});
-						Ok( LC::UnixThread(lc, ts))
+						let mut r = Reader::new(&file[ts_off..], endian);
+						let mut regs = [0u32; 16];
+						for k in range(0, 16) {regs[k] = try!(r.u32())};
+						Ok(LC::UnixThread(LoadCommand {head: lch, body: th},
+							ThreadState::ThreadStateX86(regs)))
 					},
 					(ThreadStateFlavorX86_64, 42) => {
-						let ts = ThreadState::ThreadStateX86_64(
-							unsafe {reinterpret_copy(&file[offset+lc_size..])});
-						Ok(LC::UnixThread(lc, ts))
+						let mut r = Reader::new(&file[ts_off..], endian);
+						let mut regs = [0u64; 21];
+						for k in range(0, 21) {regs[k] = try!(r.u64())};
+						Ok(LC::UnixThread(LoadCommand {head: lch, body: th},
+							ThreadState::ThreadStateX86_64(regs)))
 					},
 					(f, c) => Err(UnrecognizedThreadState(f.0, c)),
 				}
@@ -191,44 +379,119 @@ fn decode_macho(file: &[u8]) -> Result<MachO, Error> {
 	Ok(MachO {header: h, loads: l})
 }
 
-// Write a Mach-O into A.out. 32-bit only
+/// A section flattened out of a 32- or 64-bit segment, tagged with its
+/// segment's name and VM base so `to_aout`'s text/data/bss/relocation pipeline
+/// can treat both pointer widths uniformly.
+struct FlatSection<'a> {
+	segname: macho::U8N16,
+	sectname: macho::U8N16,
+	vmaddr: u64,
+	data: &'a [u8],
+	relocs: &'a [macho::RelocationInfo],
+	size: u64,
+}
+
+// Write a Mach-O into A.out.
 fn to_aout(m: &MachO) -> Result<AOut9, Error> {
 	use macho::LC;
 	use aout9::*;
 
 	if m.is_dynamic() {return Err(DynamicUnsupported)};
 
-	let text = m.loads.iter()
-		.filter_map(|lc| match lc{
-			&LC::Segment32(ref c, ref sects) => Some((c, sects)), _ => None})
-		.filter(|&(c,_)| &c.body.segname[0..6] == b"__TEXT")
-		.flat_map(|(_,sects)| sects.iter().flat_map(|&(ref __, ref d)| d.iter()))
+	// Flatten both 32- and 64-bit segments into a pointer-width-agnostic list
+	// of sections, so amd64 (`Segment64`) inputs feed the same text/data/bss
+	// and relocation pipeline as i386 (`Segment32`).
+	let mut sects: Vec<FlatSection> = Vec::new();
+	for lc in m.loads.iter() {
+		match lc {
+			&LC::Segment32(ref c, ref ss) => for &(ref s, ref d, ref r) in ss.iter() {
+				sects.push(FlatSection {
+					segname: c.body.segname, sectname: s.sectname,
+					vmaddr: c.body.vmaddr as u64, data: &d[..], relocs: &r[..],
+					size: s.size as u64});
+			},
+			&LC::Segment64(ref c, ref ss) => for &(ref s, ref d, ref r) in ss.iter() {
+				sects.push(FlatSection {
+					segname: c.body.segname, sectname: s.sectname,
+					vmaddr: c.body.vmaddr, data: &d[..], relocs: &r[..],
+					size: s.size});
+			},
+			_ => {},
+		}
+	}
+
+	let mut text: Vec<u8> = sects.iter()
+		.filter(|s| &s.segname[0..6] == b"__TEXT")
+		.flat_map(|s| s.data.iter())
 		.cloned()
 		.collect();
 
-	let data = m.loads.iter()
-		.filter_map(|lc| match lc{
-			&LC::Segment32(ref c, ref sects) => Some((c, sects)), _ => None})
-		.filter(|&(c,_)| &c.body.segname[0..6] == b"__DATA")
-		.flat_map(|(_,sects)| sects.iter().flat_map(|&(ref __, ref d)| d.iter()))
+	let data = sects.iter()
+		.filter(|s| &s.segname[0..6] == b"__DATA")
+		.flat_map(|s| s.data.iter())
 		.cloned()
 		.collect();
 
-	let bss = m.loads.iter()
-		.filter_map(|lc| match lc{
-			&LC::Segment32(ref c, ref sects) => Some((c, sects)), _ => None})
-		.filter(|&(c,_)| &c.body.segname[0..6] == b"__DATA")
-		.flat_map(|(_,sects)| sects.iter())
-		.filter(|sect| &sect.0.sectname[0..5] == b"__bss")
-		.map(|sect| sect.0.size)
+	let bss: u64 = sects.iter()
+		.filter(|s| &s.segname[0..6] == b"__DATA" && &s.sectname[0..5] == b"__bss")
+		.map(|s| s.size)
 		.sum();
 
+	// The VM base of the TEXT segment, against which symbol values are
+	// relativized.
+	let text_vmaddr = sects.iter()
+		.filter(|s| &s.segname[0..6] == b"__TEXT")
+		.map(|s| s.vmaddr)
+		.next().unwrap_or(0);
+
+	// Resolve the entry point by symbol name rather than assuming it is the
+	// first symbol in the TEXT. `ENTRY_SYMBOLS` is consulted in priority
+	// order: a later name is used only when no earlier one is defined.
+	let entry = ENTRY_SYMBOLS.iter().filter_map(|want| {
+		m.loads.iter()
+			.filter_map(|lc| match lc {
+				&LC::Symtab(_, ref syms) => Some(syms), _ => None})
+			.flat_map(|syms| syms.iter())
+			.filter(|&&(_, ref nl)| !nl.is_stab())
+			.find(|&&(ref name, _)| name == want)
+			// Relativize to the Plan 9 text base; wrap so a crafted symbol
+			// below `text_vmaddr` cannot underflow-panic in debug builds.
+			.map(|&(_, ref nl)| nl.n_value()
+				.wrapping_sub(text_vmaddr).wrapping_add(0x1000))
+	}).next();
+
+	let entry = match entry {
+		Some(e) => e,
+		None => return Err(NoEntrySymbol),
+	};
+
+	// Patch the concatenated text in place rather than hoping it is
+	// position-independent. Symbol values are relativized to the Plan 9 text
+	// base of 0x1000.
+	let syms = m.loads.iter()
+		.filter_map(|lc| match lc {
+			&LC::Symtab(_, ref syms) => Some(syms), _ => None})
+		.next();
+	let mut base = 0usize;
+	for s in sects.iter().filter(|s| &s.segname[0..6] == b"__TEXT") {
+		try!(apply_relocs(&mut text[..], base, s.relocs, syms, text_vmaddr,
+			m.header.cputype as u32));
+		base += s.data.len();
+	}
+
+	// x86-64 Mach-O inputs target amd64 Plan 9; every other CPU type is
+	// emitted as i386.
+	let magic = match m.header.cputype as u32 {
+		c if c == macho::CpuType::X86_64 as u32 => Magic::S,
+		_ => Magic::I,
+	};
+
 	Ok(AOut9 {
-		magic: Magic::I,
+		magic: magic,
 		text: text,
 		data: data,
-		bss: bss as u64,
-		entry: 0x20,
+		bss: bss,
+		entry: entry,
 	})
 }
 
@@ -237,9 +500,75 @@ fn main() {
 	let mut v = Vec::with_capacity(f.metadata().unwrap().len() as usize);
 	f.read_to_end(&mut v).unwrap();
 
-	let decoded = decode_macho(&v[..]).unwrap();
+	let slice = select_arch(&v[..], macho::CpuType::X86_64).unwrap();
+	let decoded = decode_macho(slice).unwrap();
 	let f = std::fs::File::create("aout9").unwrap();
 	to_aout(&decoded).unwrap().write_to(f).unwrap();
 
 	println!("{:x}", decoded.loads.len());
 }
+
+#[cfg(test)]
+mod tests {
+	use super::decode_macho;
+	use macho::LC;
+
+	fn put_u32(v: &mut Vec<u8>, w: u32) {
+		v.push(w as u8); v.push((w >> 8) as u8);
+		v.push((w >> 16) as u8); v.push((w >> 24) as u8);
+	}
+
+	/// A 16-byte NUL-padded name field.
+	fn put_name(v: &mut Vec<u8>, name: &[u8]) {
+		for i in range(0, 16) {v.push(if i < name.len() {name[i]} else {0})};
+	}
+
+	/// A little-endian 32-bit stripped, section-data-only executable: one
+	/// `__TEXT` segment with a single `__text` section, no symbol table and no
+	/// relocations, its four bytes of text packed immediately behind the load
+	/// commands. This is exactly the shape `write_to` emits, so this file
+	/// round-trips byte-for-byte. `write_to` lays every section out directly
+	/// behind the load commands, so a file with any other layout (a segment at
+	/// file offset 0, padding before section data, a `__LINKEDIT` region) does
+	/// not.
+	fn stripped_exe() -> Vec<u8> {
+		// 28 header + (8 + 48 + 68) segment command = 152 before section data.
+		const SIZEOFCMDS: u32 = 8 + 48 + 68;
+		const OFF: u32 = 28 + SIZEOFCMDS;	// 152: where the section data lands
+		const SIZE: u32 = 4;				// four bytes of text
+
+		let mut v = Vec::new();
+		// Header with a single load command.
+		for w in [0xfeedface, 7, 3, 2, 1, SIZEOFCMDS, 1].iter() {put_u32(&mut v, *w)};
+		// Segment32 load command.
+		put_u32(&mut v, 0x1); put_u32(&mut v, SIZEOFCMDS);
+		put_name(&mut v, b"__TEXT");
+		for w in [0x1000, 0x1000, OFF, SIZE, 7, 5, 1, 0].iter() {put_u32(&mut v, *w)};
+		// Its single section, no relocations.
+		put_name(&mut v, b"__text"); put_name(&mut v, b"__TEXT");
+		for w in [0x1000, SIZE, OFF, 0, 0, 0, 0, 0, 0].iter() {put_u32(&mut v, *w)};
+		// Section payload.
+		for b in [0x90u8, 0x90, 0x90, 0x90].iter() {v.push(*b)};
+		v
+	}
+
+	#[test]
+	fn round_trips() {
+		let bytes = stripped_exe();
+		let decoded = decode_macho(&bytes[..]).unwrap();
+		// A segment with a real section must have decoded, so the write path is
+		// actually exercised.
+		assert!(decoded.loads.iter().any(|lc| match lc {
+			&LC::Segment32(_, ref s) => !s.is_empty(), _ => false}));
+
+		// A stripped, section-data-only executable re-emits byte-for-byte.
+		let mut out = Vec::new();
+		decoded.write_to(&mut out).unwrap();
+		assert_eq!(bytes, out);
+
+		// And the form is stable under a second round trip.
+		let mut out2 = Vec::new();
+		decode_macho(&out[..]).unwrap().write_to(&mut out2).unwrap();
+		assert_eq!(out, out2);
+	}
+}